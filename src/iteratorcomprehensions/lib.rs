@@ -15,156 +15,62 @@ pub mod macros {
   #![macro_escape]
 
   /**
-    Turns a comma separated list of identifiers into a nested tuple pattern.
+    Main implementation of the `iterator!()` extension.
+
+    Expands right-to-left over the generator list: the innermost generator becomes a `.filter()`
+    chain followed by a final `.map()`, and each enclosing generator wraps that as a `.flat_map()`
+    whose `move` closure captures its own binding directly. Each variable stays in scope through
+    ordinary closure capture, so no tuple of accumulated environment variables is ever built.
+
+    Because each enclosing generator's binding is captured by a nested `move` closure, a
+    comprehension with two or more generators needs any outside state referenced by `map_expr` or
+    by a `filter_expr` of any generator after the first to be `Copy` (a `&T` reference works, since
+    references are `Copy`). An owned
+    non-`Copy` value (say a `String`) can't be captured directly by such an expression, since the
+    outer `flat_map` closure would have to move it out of its own environment on every call, which
+    only the first call could succeed at. Pass a reference to the value instead:
 
     ```notrust
-    arglist(i) -> i
-    arglist(i, j) -> (j, i)
-    arglist(i, j, k) -> (k, (j, i))
+    let prefix = "x".to_string();
+    let p = &prefix;
+    iterator!( format!("{}{}{}", p, i, j) for i in range(0i, 3i) for j in range(0i, 3i) )
     ```
   */
   #[macro_export]
-  macro_rules! arglist(
-    (
-      $var:ident
-    ) => (
-      $var
-    );
-    (
-      $var:ident $(, $vars:ident)+
-    ) => (
-      (arglist!($($vars),+), $var)
-    );
-  )
-
-  /**
-    Main implementation of the `iterator!()` extension.
-  */
-  #[macro_export]
   macro_rules! iterator_tail(
     (
-      (),
-      (),
-      (
-        $map:expr
-        for $var:ident in $gen:expr
-        $(
-          for $vars:ident in $gens:expr
-          $(if $filters:expr)*
-        )*
-      )
-    ) => (
-      iterator_tail!(
-        (
-          $gen
-        ),
-        ($var),
-        (
-          $map
-          $(
-            for $vars in $gens
-            $(if $filters)*
-          )*
-        )
-      )
-    );
-    (
-      (),
-      (),
-      (
-        $map:expr
-        for $var:ident in $gen:expr
-        if $filter:expr
-        $(
-          for $vars:ident in $gens:expr
-          $(if $filters:expr)*
-        )*
-      )
+      $map:expr,
+      for $pat:pat in $gen:expr
+      $(if $filters:expr)*
     ) => (
-      iterator_tail!(
-        (
-          $gen
-          .filter(|&$var| { $filter } )
-        ),
-        ($var),
-        (
-          $map
-          $(
-            for $vars in $gens
-            $(if $filters)*
-          )*
-        )
-      )
+      ::std::iter::IntoIterator::into_iter($gen)
+      $(.filter(move |&$pat| { $filters }))*
+      .map(move |$pat| { $map })
     );
     (
-      ($head:expr),
-      ($($envs:ident),+),
-      (
-        $map:expr
-        for $var:ident in $gen:expr
-        $(
-          for $vars:ident in $gens:expr
-          $(if $filters:expr)*
-        )*
-      )
-    ) => (
-      iterator_tail!(
-        (
-          $head
-          .flat_map(|arglist!($($envs),+)| {
-            ::std::iter::Repeat::new(arglist!($($envs),+)).zip($gen)
-          })
-        ),
-        ($var $(, $envs)*),
-        (
-          $map
-          $(
-            for $vars in $gens
-            $(if $filters)*
-          )*
-        )
-      )
-    );
-    (
-      ($head:expr),
-      ($($envs:ident),+),
-      (
-        $map:expr
-        for $var:ident in $gen:expr
-        if $filter:expr
-        $(
-          for $vars:ident in $gens:expr
-          $(if $filters:expr)*
-        )*
-      )
+      $map:expr,
+      for $pat:pat in $gen:expr
+      $(if $filters:expr)*
+      for $npat:pat in $ngen:expr
+      $(if $nfilters:expr)*
+      $(
+        for $mpats:pat in $mgens:expr
+        $(if $mfilters:expr)*
+      )*
     ) => (
-      iterator_tail!(
-        (
-          $head
-          .flat_map(|arglist!($($envs),+)| {
-            ::std::iter::Repeat::new(arglist!($($envs),+)).zip($gen)
-          })
-          .filter(|&arglist!($var $(, $envs)+)| { $filter })
-        ),
-        ($var $(, $envs)*),
-        (
-          $map
+      ::std::iter::IntoIterator::into_iter($gen)
+      $(.filter(move |&$pat| { $filters }))*
+      .flat_map(move |$pat| {
+        iterator_tail!(
+          $map,
+          for $npat in $ngen
+          $(if $nfilters)*
           $(
-            for $vars in $gens
-            $(if $filters)*
+            for $mpats in $mgens
+            $(if $mfilters)*
           )*
         )
-      )
-    );
-    (
-      ($head:expr),
-      ($($envs:ident),+),
-      (
-        $map:expr
-      )
-    ) => (
-      $head
-      .map(|arglist!($($envs),+)| { $map })
+      })
     );
   )
 
@@ -173,19 +79,23 @@ pub mod macros {
 
     ```notrust
     iterator!(
-      map_expr for var_1 in gen_expr_1 [if filter_expr_1]
-      [… for var_n in gen_expr_n [if filter_expr]]
+      map_expr for pat_1 in gen_expr_1 [if filter_expr_1 [if filter_expr_1']*]
+      [… for pat_n in gen_expr_n [if filter_expr_n [if filter_expr_n']*]]
     )
     ```
 
-    * `var_1`… `var_n` identify the iteration variables associated with each of the nested
-      iterators.
-    * `gen_expr_1`… `gen_expr_n` are expressions that evaluate to an `Iterator`. `gen_expr_i` can
-      refer to all "outer" iteration variables `var_1`… `var_(i-1)`.
+    * `pat_1`… `pat_n` are irrefutable patterns that bind the iteration variables associated with
+      each of the nested iterators, exactly as in a `for` loop — plain identifiers, tuples, struct
+      destructures and reference patterns such as `&x` are all allowed.
+    * `gen_expr_1`… `gen_expr_n` are expressions that evaluate to anything implementing
+      `IntoIterator` — an `Iterator` itself, but also a collection such as a `Vec` or a reference
+      to one, exactly like the generator in a `for` loop. `gen_expr_i` can refer to all "outer"
+      iteration variables bound by `pat_1`… `pat_(i-1)`.
     * `map_expr` is an expression that constructs the elements of the iterator comprehension from
       the iteration variables.
-    * `filter_expr_1`… `filter_expr_n` are expressions that evaluate to a boolean which filters
-      the iterator elements based on the "outer" iteration variables.
+    * each generator may be followed by zero or more `if filter_expr` clauses, all of which must
+      hold for an element to be included. The filters can refer to the iteration variables bound
+      by that generator and all "outer" ones.
 
     `iterator!()` evaluates to an expression which itself implements the `Iterator` trait.
 
@@ -201,29 +111,139 @@ pub mod macros {
     ```notrust
     (0,0), (1,1), (2,0), (2,2)
     ```
+
+    `iterator!()` also accepts a postfix form with the generators and filters written first,
+    comma separated, and the `map_expr` trailing after a `;`:
+
+    ```notrust
+    iterator!(
+      for pat_1 in gen_expr_1, … , for pat_n in gen_expr_n
+      $(, if filter_expr)*
+      ; map_expr
+    )
+    ```
+    which is equivalent to `iterator!(map_expr for pat_1 in gen_expr_1 … for pat_n in gen_expr_n
+    $(if filter_expr)*)` — the trailing filters apply to the innermost generator, exactly as if
+    they had been written directly after it in the head-first form. This lets people who think
+    "generators then projection" use the macro without reordering.
+
+    ```notrust
+    iterator!(for i in range(0i, 5i), for j in range(0i, 5i), if i < j; 5 * i + j)
+    ```
   */
   #[macro_export]
   macro_rules! iterator(
+    (
+      $(for $pats:pat in $gens:expr),+
+      $(, if $filters:expr)*
+      ; $map:expr
+    ) => (
+      iterator!(
+        $map
+        $(
+          for $pats in $gens
+        )+
+        $(if $filters)*
+      )
+    );
     (
       $map:expr
       $(
-        for $vars:ident in $gens:expr
+        for $pats:pat in $gens:expr
         $(if $filters:expr)*
       )+
     ) => (
       iterator_tail!(
-        (),
-        (),
-        (
-          $map
-          $(
-            for $vars in $gens
-            $(if $filters)*
-          )+
-        )
+        $map,
+        $(
+          for $pats in $gens
+          $(if $filters)*
+        )+
       )
     );
   )
+
+  /**
+    Like `iterator!()`, but collects the result into a `Vec`.
+
+    ```notrust
+    vector!( i * i for i in range(0i, 5i) if i % 2 == 0 )
+    ```
+    evaluates to `vec!(0, 4, 16)`.
+  */
+  #[macro_export]
+  macro_rules! vector(
+    (
+      $map:expr
+      $(
+        for $pats:pat in $gens:expr
+        $(if $filters:expr)*
+      )+
+    ) => (
+      iterator!(
+        $map
+        $(
+          for $pats in $gens
+          $(if $filters)*
+        )+
+      ).collect::<Vec<_>>()
+    );
+  )
+
+  /**
+    Like `iterator!()`, but collects the result into a `HashSet`.
+
+    ```notrust
+    set!( i % 3 for i in range(0i, 10i) )
+    ```
+    evaluates to the set `{0, 1, 2}`.
+  */
+  #[macro_export]
+  macro_rules! set(
+    (
+      $map:expr
+      $(
+        for $pats:pat in $gens:expr
+        $(if $filters:expr)*
+      )+
+    ) => (
+      iterator!(
+        $map
+        $(
+          for $pats in $gens
+          $(if $filters)*
+        )+
+      ).collect::<::std::collections::HashSet<_>>()
+    );
+  )
+
+  /**
+    A dict-comprehension form of `iterator!()` that collects into a `HashMap`. The head of the
+    comprehension is `key_expr => value_expr` instead of a single `map_expr`.
+
+    ```notrust
+    map!( i => i * i for i in range(0i, 10i) if i % 2 == 0 )
+    ```
+    evaluates to the map `{0: 0, 2: 4, 4: 16, 6: 36, 8: 64}`.
+  */
+  #[macro_export]
+  macro_rules! map(
+    (
+      $key:expr => $value:expr
+      $(
+        for $pats:pat in $gens:expr
+        $(if $filters:expr)*
+      )+
+    ) => (
+      iterator!(
+        ($key, $value)
+        $(
+          for $pats in $gens
+          $(if $filters)*
+        )+
+      ).collect::<::std::collections::HashMap<_, _>>()
+    );
+  )
 }
 
 #[cfg(test)]
@@ -252,6 +272,27 @@ mod tests {
     assert_eq!(xs, vec!(2));
   }
 
+  #[test]
+  fn iterator1_multi_filter_test() {
+    let xs: Vec<int> = iterator!(
+      i for i in range(0i, 100i) if i % 2 == 0 if i % 3 == 0
+    ).collect();
+    assert_eq!(xs, vec!(0, 6, 12, 18, 24, 30, 36, 42, 48, 54, 60, 66, 72, 78, 84, 90, 96));
+  }
+
+  #[test]
+  fn iterator1_into_iter_test() {
+    let xs: Vec<int> = iterator!( x * 2 for x in vec!(1i, 2i, 3i) ).collect();
+    assert_eq!(xs, vec!(2, 4, 6));
+  }
+
+  #[test]
+  fn iterator1_destructure_test() {
+    let pairs = vec!((1i, 2i), (3i, 4i));
+    let xs: Vec<int> = iterator!( a + b for &(a, b) in pairs.iter() ).collect();
+    assert_eq!(xs, vec!(3, 7));
+  }
+
   #[test]
   fn iterator2_map_test() {
     let xs: Vec<int> = iterator!( i * j for i in range(1i, 3i) for j in range(2i, 4i) ).collect();
@@ -274,6 +315,38 @@ mod tests {
     assert_eq!(xs, vec!((0, 0), (1, 1), (2, 0), (2, 2)));
   }
 
+  #[test]
+  fn iterator2_postfix_test() {
+    let xs: Vec<int> = iterator!(
+      for i in range(0i, 5i), for j in range(0i, 5i), if i < j; 5 * i + j
+    ).collect();
+    assert_eq!(xs, vec!(1, 2, 3, 4, 7, 8, 9, 13, 14, 19));
+  }
+
+  #[test]
+  fn iterator2_destructure_test() {
+    let pairs = vec!((1i, 2i), (3i, 4i));
+    let xs: Vec<int> = iterator!(
+      k * (a + b) for &(a, b) in pairs.iter() for k in range(0i, 2i)
+    ).collect();
+    assert_eq!(xs, vec!(0, 3, 0, 7));
+  }
+
+  #[test]
+  fn iterator2_outer_capture_test() {
+    // A comprehension with 2+ generators wraps each enclosing generator's binding in a `move`
+    // closure, so non-`Copy` state captured from outside the comprehension must be passed in as
+    // a reference (references are `Copy`) rather than moved in directly.
+    let prefix = "n".to_string();
+    let p = &prefix;
+    let xs: Vec<String> = iterator!(
+      format!("{}{}{}", p, i, j) for i in range(0i, 2i) for j in range(0i, 2i)
+    ).collect();
+    assert_eq!(xs, vec!(
+      "n00".to_string(), "n01".to_string(), "n10".to_string(), "n11".to_string()
+    ));
+  }
+
   #[test]
   fn iterator3_map_test() {
     let a = vec!(1i, 2i);
@@ -317,4 +390,26 @@ mod tests {
     assert_eq!(xs.next().unwrap(), (0, 0, 0, 0, 0, 1));
     assert_eq!(xs.last().unwrap(), (4, 4, 4, 4, 4, 4));
   }
+
+  #[test]
+  fn vector_test() {
+    let xs = vector!( i * i for i in range(0i, 5i) if i % 2 == 0 );
+    assert_eq!(xs, vec!(0, 4, 16));
+  }
+
+  #[test]
+  fn set_test() {
+    let xs = set!( i % 3 for i in range(0i, 10i) );
+    assert_eq!(xs.len(), 3);
+    assert!(xs.contains(&0));
+    assert!(xs.contains(&1));
+    assert!(xs.contains(&2));
+  }
+
+  #[test]
+  fn map_test() {
+    let xs = map!( i => i * i for i in range(0i, 10i) if i % 2 == 0 );
+    assert_eq!(xs.len(), 5);
+    assert_eq!(*xs.get(&4).unwrap(), 16);
+  }
 }